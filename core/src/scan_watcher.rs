@@ -0,0 +1,283 @@
+/// This file holds a long-running watcher that polls `Scan`s and emits typed diff events
+use crate::node_interface::get_header_id_for_height;
+use crate::scans::{Result as ScanResult, Scan, ScanID};
+
+use ergo_lib::ergotree_ir::chain::ergo_box::{BoxId, ErgoBox};
+use ergo_lib::ergotree_ir::chain::token::TokenId;
+use log::warn;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::Arc;
+use std::thread;
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+/// Identifier of an Ergo block header, as returned by the node.
+pub type HeaderId = String;
+
+/// How many distinct inclusion heights of rollback cursors to keep per scan
+const ROLLBACK_WINDOW_SIZE: usize = 720;
+
+/// An event describing how a scan's UTXO set changed between two polls.
+#[derive(Debug, Clone)]
+pub enum ScanEvent {
+    /// A box matching the scan appeared that wasn't present on the previous poll.
+    BoxCreated(ErgoBox),
+    /// A box matching the scan on the previous poll is no longer present (spent).
+    BoxSpent(BoxId),
+    /// A box was replaced by a new box carrying the same identifying token.
+    BoxUpdated { old: ErgoBox, new: ErgoBox },
+    /// The chain reorged at `from_height`; every cached box at or above it is invalidated.
+    Rollback { from_height: u32 },
+}
+
+/// Polls one or more `Scan`s on a fixed interval and emits `ScanEvent`s over a channel
+pub struct ScanWatcher {
+    scans: Vec<Scan>,
+    poll_interval: Duration,
+}
+
+/// Handle to a running `ScanWatcher` background thread.
+pub struct ScanWatcherHandle {
+    stop: Arc<AtomicBool>,
+    join_handle: JoinHandle<()>,
+}
+
+impl ScanWatcherHandle {
+    /// Signals the watcher thread to stop and blocks until it exits.
+    pub fn stop(self) {
+        self.stop.store(true, Ordering::Relaxed);
+        let _ = self.join_handle.join();
+    }
+}
+
+impl ScanWatcher {
+    /// Spawns a background thread polling `scans`, returning the event receiver and a stop handle
+    pub fn new(scans: Vec<Scan>, poll_interval: Duration) -> (Receiver<ScanEvent>, ScanWatcherHandle) {
+        let (tx, rx) = channel();
+        let stop = Arc::new(AtomicBool::new(false));
+        let thread_stop = stop.clone();
+        let watcher = ScanWatcher {
+            scans,
+            poll_interval,
+        };
+        let join_handle = thread::spawn(move || watcher.run(tx, thread_stop));
+        (rx, ScanWatcherHandle { stop, join_handle })
+    }
+
+    fn run(&self, tx: Sender<ScanEvent>, stop: Arc<AtomicBool>) {
+        let mut snapshots: HashMap<ScanID, HashMap<BoxId, (ErgoBox, u32)>> = HashMap::new();
+        let mut cursors: HashMap<ScanID, BTreeMap<u32, HeaderId>> = HashMap::new();
+        while !stop.load(Ordering::Relaxed) {
+            for scan in &self.scans {
+                let events = match self.poll_scan(scan, &mut snapshots, &mut cursors) {
+                    Ok(events) => events,
+                    Err(e) => {
+                        warn!("ScanWatcher failed to poll scan '{:?}': {}", scan, e);
+                        continue;
+                    }
+                };
+                for event in events {
+                    // The receiver was dropped; nowhere left to report events, so stop.
+                    if tx.send(event).is_err() {
+                        return;
+                    }
+                }
+            }
+            thread::sleep(self.poll_interval);
+        }
+    }
+
+    /// Checks for a rollback, diffs `scan`'s current boxes against the last snapshot, and returns the events
+    fn poll_scan(
+        &self,
+        scan: &Scan,
+        snapshots: &mut HashMap<ScanID, HashMap<BoxId, (ErgoBox, u32)>>,
+        cursors: &mut HashMap<ScanID, BTreeMap<u32, HeaderId>>,
+    ) -> ScanResult<Vec<ScanEvent>> {
+        let mut events = Vec::new();
+
+        let scan_cursors = cursors.entry(scan.id().clone()).or_default();
+        if let Some(from_height) = detect_rollback(scan_cursors, get_header_id_for_height) {
+            events.push(ScanEvent::Rollback { from_height });
+            scan_cursors.retain(|height, _| *height < from_height);
+            if let Some(snapshot) = snapshots.get_mut(scan.id()) {
+                snapshot.retain(|_, (_, height)| *height < from_height);
+            }
+        }
+
+        let current: HashMap<BoxId, (ErgoBox, u32)> = scan
+            .get_boxes_with_inclusion_height()?
+            .into_iter()
+            .map(|(b, height)| (b.box_id(), (b, height)))
+            .collect();
+        for (_, height) in current.values() {
+            record_cursor(scan_cursors, *height, get_header_id_for_height)?;
+        }
+        let previous = snapshots.entry(scan.id().clone()).or_default();
+
+        let created: Vec<ErgoBox> = current
+            .iter()
+            .filter(|(id, _)| !previous.contains_key(id))
+            .map(|(_, (b, _))| b.clone())
+            .collect();
+        let spent: Vec<ErgoBox> = previous
+            .iter()
+            .filter(|(id, _)| !current.contains_key(id))
+            .map(|(_, (b, _))| b.clone())
+            .collect();
+        let (created, spent, updated) = partition_by_identity(created, spent, identity_token);
+
+        events.extend(updated.into_iter().map(|(old, new)| ScanEvent::BoxUpdated { old, new }));
+        events.extend(created.into_iter().map(ScanEvent::BoxCreated));
+        events.extend(spent.into_iter().map(|b| ScanEvent::BoxSpent(b.box_id())));
+
+        *previous = current;
+        Ok(events)
+    }
+}
+
+/// Returns the box's first token id, used as a stand-in for box "identity" across box ids
+fn identity_token(b: &ErgoBox) -> Option<TokenId> {
+    b.tokens.as_ref().and_then(|ts| ts.get(0)).map(|t| t.token_id)
+}
+
+/// Splits `created`/`spent` into genuine creates/spends plus updates for shared-identity pairs
+fn partition_by_identity<T, K, F>(
+    created: Vec<T>,
+    mut spent: Vec<T>,
+    identity: F,
+) -> (Vec<T>, Vec<T>, Vec<(T, T)>)
+where
+    F: Fn(&T) -> Option<K>,
+    K: PartialEq,
+{
+    let mut still_created = Vec::new();
+    let mut updated = Vec::new();
+    for item in created {
+        let matched_pos =
+            identity(&item).and_then(|k| spent.iter().position(|s| identity(s) == Some(k)));
+        match matched_pos {
+            Some(pos) => updated.push((spent.remove(pos), item)),
+            None => still_created.push(item),
+        }
+    }
+    (still_created, spent, updated)
+}
+
+/// Returns the lowest height at which `fetch_header_id` no longer matches the recorded cursor
+fn detect_rollback<F>(cursors: &BTreeMap<u32, HeaderId>, fetch_header_id: F) -> Option<u32>
+where
+    F: Fn(u32) -> ScanResult<HeaderId>,
+{
+    let mut rolled_back_from = None;
+    for (&height, recorded_header_id) in cursors.iter() {
+        match fetch_header_id(height) {
+            Ok(current_header_id) if &current_header_id != recorded_header_id => {
+                rolled_back_from = Some(rolled_back_from.map_or(height, |h: u32| h.min(height)));
+            }
+            Err(e) => warn!("Failed to fetch header id at height {}: {}", height, e),
+            _ => (),
+        }
+    }
+    rolled_back_from
+}
+
+/// Records the header id for `height` if untracked, evicting the oldest once the window fills
+fn record_cursor<F>(
+    cursors: &mut BTreeMap<u32, HeaderId>,
+    height: u32,
+    fetch_header_id: F,
+) -> ScanResult<()>
+where
+    F: Fn(u32) -> ScanResult<HeaderId>,
+{
+    if cursors.contains_key(&height) {
+        return Ok(());
+    }
+    let header_id = fetch_header_id(height)?;
+    cursors.insert(height, header_id);
+    while cursors.len() > ROLLBACK_WINDOW_SIZE {
+        if let Some(&oldest) = cursors.keys().next() {
+            cursors.remove(&oldest);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn partition_by_identity_reports_shared_identity_as_update() {
+        let created = vec!["a", "b"];
+        let spent = vec!["c", "d"];
+        let identity = |s: &&str| match *s {
+            "a" | "c" => Some(1),
+            _ => None,
+        };
+        let (created, spent, updated) = partition_by_identity(created, spent, identity);
+        assert_eq!(created, vec!["b"]);
+        assert_eq!(spent, vec!["d"]);
+        assert_eq!(updated, vec![("c", "a")]);
+    }
+
+    #[test]
+    fn partition_by_identity_with_no_shared_identity_is_plain_create_and_spend() {
+        let (created, spent, updated) =
+            partition_by_identity(vec!["a"], vec!["b"], |_: &&str| None::<i32>);
+        assert_eq!(created, vec!["a"]);
+        assert_eq!(spent, vec!["b"]);
+        assert!(updated.is_empty());
+    }
+
+    #[test]
+    fn detect_rollback_returns_none_when_all_headers_still_match() {
+        let mut cursors = BTreeMap::new();
+        cursors.insert(100, "h100".to_string());
+        cursors.insert(200, "h200".to_string());
+        assert_eq!(
+            detect_rollback(&cursors, |height| Ok(format!("h{}", height))),
+            None
+        );
+    }
+
+    #[test]
+    fn detect_rollback_picks_lowest_of_several_stale_heights() {
+        let mut cursors = BTreeMap::new();
+        cursors.insert(100, "h100".to_string());
+        cursors.insert(200, "stale".to_string());
+        cursors.insert(300, "also-stale".to_string());
+        assert_eq!(
+            detect_rollback(&cursors, |height| Ok(format!("h{}", height))),
+            Some(200)
+        );
+    }
+
+    #[test]
+    fn record_cursor_is_idempotent_for_an_already_known_height() {
+        let mut cursors = BTreeMap::new();
+        record_cursor(&mut cursors, 5, |_| Ok("first".to_string())).unwrap();
+        record_cursor(&mut cursors, 5, |_| Ok("second".to_string())).unwrap();
+        assert_eq!(cursors.get(&5), Some(&"first".to_string()));
+    }
+
+    #[test]
+    fn record_cursor_evicts_oldest_height_once_window_is_full() {
+        let mut cursors = BTreeMap::new();
+        for height in 0..ROLLBACK_WINDOW_SIZE as u32 {
+            record_cursor(&mut cursors, height, |h| Ok(format!("h{}", h))).unwrap();
+        }
+        assert_eq!(cursors.len(), ROLLBACK_WINDOW_SIZE);
+        assert!(cursors.contains_key(&0));
+
+        let next_height = ROLLBACK_WINDOW_SIZE as u32;
+        record_cursor(&mut cursors, next_height, |h| Ok(format!("h{}", h))).unwrap();
+
+        assert_eq!(cursors.len(), ROLLBACK_WINDOW_SIZE);
+        assert!(!cursors.contains_key(&0));
+        assert!(cursors.contains_key(&next_height));
+    }
+}
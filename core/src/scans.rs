@@ -2,8 +2,9 @@ use crate::contracts::pool::PoolContract;
 use crate::contracts::refresh::RefreshContract;
 /// This file holds logic related to UTXO-set scans
 use crate::node_interface::{
-    address_to_bytes, address_to_raw_for_register, get_scan_boxes, register_scan, serialize_box,
-    serialize_boxes,
+    address_to_bytes, address_to_raw_for_register, deregister_scan, get_scan_boxes,
+    get_scan_boxes_filtered, get_scan_boxes_with_inclusion_height, get_scan_tracking_rule,
+    register_scan, serialize_box, serialize_boxes,
 };
 use crate::print_and_log;
 
@@ -11,7 +12,8 @@ use derive_more::From;
 use ergo_lib::ergotree_ir::chain::ergo_box::ErgoBox;
 use ergo_node_interface::node_interface::NodeError;
 use json::JsonValue;
-use log::info;
+use log::{info, warn};
+use std::collections::HashMap;
 use thiserror::Error;
 
 /// Integer which is provided by the Ergo node to reference a given scan.
@@ -29,26 +31,37 @@ pub enum ScanError {
     FailedToRegister,
     #[error("IO error: {0}")]
     IoError(std::io::Error),
+    #[error("failed to parse scanIDs.json: {0}")]
+    JsonParseError(json::Error),
 }
 
 /// A `Scan` is a name + scan_id for a given scan with extra methods for acquiring boxes.
 #[derive(Debug, Clone)]
 pub struct Scan {
-    name: &'static str,
+    name: String,
     id: ScanID,
 }
 
+/// Bounds on confirmation depth and inclusion height used to restrict which boxes a scan returns
+#[derive(Debug, Clone, Copy, Default)]
+pub struct BoxFilter {
+    pub min_confirmations: Option<u32>,
+    pub max_confirmations: Option<u32>,
+    pub min_inclusion_height: Option<u32>,
+    pub max_inclusion_height: Option<u32>,
+}
+
 impl Scan {
     /// Create a new `Scan` with provided name & scan_id
-    pub fn new(name: &'static str, scan_id: &String) -> Scan {
+    pub fn new(name: &str, scan_id: &String) -> Scan {
         Scan {
-            name,
+            name: name.to_string(),
             id: scan_id.clone(),
         }
     }
 
     /// Registers a scan in the node and returns a `Scan` as a result
-    pub fn register(name: &'static str, tracking_rule: JsonValue) -> Result<Scan> {
+    pub fn register(name: &str, tracking_rule: JsonValue) -> Result<Scan> {
         let scan_json = object! {
         scanName: name,
         trackingRule: tracking_rule.clone(),
@@ -62,6 +75,65 @@ impl Scan {
         Ok(Scan::new(name, &scan_id))
     }
 
+    /// Registers a scan, reusing its ID from `scanIDs.json` if the node still has that ID
+    /// registered under the same tracking rule; an ID registered under a different rule is
+    /// deregistered and a new scan is registered in its place
+    pub fn ensure_registered(name: &str, tracking_rule: JsonValue) -> Result<Scan> {
+        let local_ids = match load_scan_ids_locally() {
+            Ok(ids) => ids,
+            Err(ScanError::IoError(e)) if e.kind() == std::io::ErrorKind::NotFound => {
+                info!("No scanIDs.json found locally, registering scans fresh");
+                HashMap::new()
+            }
+            Err(e) => {
+                warn!(
+                    "Failed to read local scanIDs.json ({}), registering scans fresh",
+                    e
+                );
+                HashMap::new()
+            }
+        };
+        let local_id = local_ids.get(name);
+        let node_tracking_rule = match local_id {
+            Some(scan_id) => get_scan_tracking_rule(scan_id)?,
+            None => None,
+        };
+        match reconcile(local_id, node_tracking_rule.as_ref(), &tracking_rule) {
+            Reconciliation::Reuse => {
+                let scan_id = local_id.expect("Reuse is only returned when local_id is Some");
+                info!("Scan '{}' already registered with ID: {}", name, scan_id);
+                Ok(Scan::new(name, scan_id))
+            }
+            Reconciliation::Stale => {
+                let scan_id = local_id.expect("Stale is only returned when local_id is Some");
+                info!(
+                    "Scan '{}' ID {} is registered under a different tracking rule, deregistering",
+                    name, scan_id
+                );
+                if let Err(e) = Scan::new(name, scan_id).deregister() {
+                    warn!("Failed to deregister stale scan '{}': {}", name, e);
+                }
+                Scan::register(name, tracking_rule)
+            }
+            Reconciliation::Missing => Scan::register(name, tracking_rule),
+        }
+    }
+
+    /// Deregisters this scan from the node, via `/scan/deregister`.
+    pub fn deregister(&self) -> Result<()> {
+        deregister_scan(&self.id)?;
+        print_and_log(&format!(
+            "Scan '{}' (ID: {}) deregistered.",
+            self.name, self.id
+        ));
+        Ok(())
+    }
+
+    /// Returns this scan's ID as registered on the node
+    pub fn id(&self) -> &ScanID {
+        &self.id
+    }
+
     /// Returns all boxes found by the scan
     pub fn get_boxes(&self) -> Result<Vec<ErgoBox>> {
         let boxes = get_scan_boxes(&self.id)?;
@@ -76,6 +148,30 @@ impl Scan {
             .ok_or(ScanError::NoBoxesFound)
     }
 
+    /// Returns all boxes found by the scan paired with each box's node-reported inclusion height
+    pub(crate) fn get_boxes_with_inclusion_height(&self) -> Result<Vec<(ErgoBox, u32)>> {
+        let boxes = get_scan_boxes_with_inclusion_height(&self.id)?;
+        Ok(boxes)
+    }
+
+    /// Returns boxes found by the scan, restricted by `filter`
+    pub fn get_boxes_filtered(&self, filter: BoxFilter) -> Result<Vec<ErgoBox>> {
+        let boxes = get_scan_boxes_filtered(&self.id, &filter)?;
+        Ok(boxes)
+    }
+
+    /// Returns the first box found by the scan with at least `min_confirmations` confirmations
+    pub fn get_confirmed_box(&self, min_confirmations: u32) -> Result<ErgoBox> {
+        let filter = BoxFilter {
+            min_confirmations: Some(min_confirmations),
+            ..Default::default()
+        };
+        self.get_boxes_filtered(filter)?
+            .into_iter()
+            .next()
+            .ok_or(ScanError::NoBoxesFound)
+    }
+
     /// Returns all boxes found by the scan
     /// serialized and ready to be used as rawInputs
     pub fn get_serialized_boxes(&self) -> Result<Vec<String>> {
@@ -91,6 +187,32 @@ impl Scan {
     }
 }
 
+/// How a locally-remembered scan ID relates to what the node currently knows about it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Reconciliation {
+    /// The local ID is registered on the node under the same tracking rule.
+    Reuse,
+    /// The local ID is registered on the node but under a different tracking rule.
+    Stale,
+    /// There is no local ID, or the node no longer knows about it.
+    Missing,
+}
+
+/// Decides how `ensure_registered` should treat a local ID, given what the node (if
+/// anything) currently has registered for it. Pulled out of `ensure_registered` so the
+/// hit/stale/missing decision table can be unit tested without a node.
+fn reconcile(
+    local_id: Option<&ScanID>,
+    node_tracking_rule: Option<&JsonValue>,
+    desired_tracking_rule: &JsonValue,
+) -> Reconciliation {
+    match (local_id, node_tracking_rule) {
+        (Some(_), Some(rule)) if rule == desired_tracking_rule => Reconciliation::Reuse,
+        (Some(_), Some(_)) => Reconciliation::Stale,
+        _ => Reconciliation::Missing,
+    }
+}
+
 /// Saves UTXO-set scans (specifically id) to scanIDs.json
 pub fn save_scan_ids_locally(scans: Vec<Scan>) -> Result<bool> {
     let mut id_json = object! {};
@@ -98,12 +220,23 @@ pub fn save_scan_ids_locally(scans: Vec<Scan>) -> Result<bool> {
         if &scan.id == "null" {
             return Err(ScanError::FailedToRegister);
         }
-        id_json[scan.name] = scan.id.into();
+        id_json[scan.name.as_str()] = scan.id.into();
     }
     std::fs::write("scanIDs.json", json::stringify_pretty(id_json, 4))?;
     Ok(true)
 }
 
+/// Loads the scan name -> scan ID mapping previously written by `save_scan_ids_locally`
+pub fn load_scan_ids_locally() -> Result<HashMap<String, ScanID>> {
+    let file_contents = std::fs::read_to_string("scanIDs.json")?;
+    let id_json = json::parse(&file_contents)?;
+    let mut scan_ids = HashMap::new();
+    for (name, id) in id_json.entries() {
+        scan_ids.insert(name.to_string(), id.to_string());
+    }
+    Ok(scan_ids)
+}
+
 /// This function registers scanning for the pool box
 pub fn register_pool_box_scan(oracle_pool_nft: &String) -> Result<Scan> {
     // ErgoTree bytes of the P2S address/script
@@ -124,7 +257,7 @@ pub fn register_pool_box_scan(oracle_pool_nft: &String) -> Result<Scan> {
         ]
     };
 
-    Scan::register("Pool Box Scan", scan_json)
+    Scan::ensure_registered("Pool Box Scan", scan_json)
 }
 
 /// This function registers scanning for the refresh box
@@ -150,7 +283,7 @@ pub fn register_refresh_box_scan(scan_name: &'static str, refresh_nft: &String)
         ]
     };
 
-    Scan::register(scan_name, scan_json)
+    Scan::ensure_registered(scan_name, scan_json)
 }
 
 /// This function registers scanning for the Epoch Preparation stage box
@@ -176,7 +309,7 @@ pub fn register_epoch_preparation_scan(
         ]
     };
 
-    Scan::register("Epoch Preparation Scan", scan_json)
+    Scan::ensure_registered("Epoch Preparation Scan", scan_json)
 }
 
 /// This function registers scanning for the oracle's personal Datapoint box
@@ -211,7 +344,7 @@ pub fn register_local_oracle_datapoint_scan(
         ]
     };
 
-    Scan::register("Local Oracle Datapoint Scan", scan_json)
+    Scan::ensure_registered("Local Oracle Datapoint Scan", scan_json)
 }
 
 /// This function registers scanning for all of the pools oracles' Datapoint boxes for datapoint collection
@@ -237,7 +370,7 @@ pub fn register_datapoint_scan(
         ]
     };
 
-    Scan::register("All Datapoints Scan", scan_json)
+    Scan::ensure_registered("All Datapoints Scan", scan_json)
 }
 
 /// This function registers scanning for any boxes in the Pool Deposit stage address
@@ -254,5 +387,44 @@ pub fn register_pool_deposit_scan(pool_deposit_address: &String) -> Result<Scan>
     };
 
     println!("{:?}", scan_json.dump());
-    Scan::register("Pool Deposits Scan", scan_json)
+    Scan::ensure_registered("Pool Deposits Scan", scan_json)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reconcile_reuses_when_node_rule_matches() {
+        let id = "scan-1".to_string();
+        let rule = object! { "predicate": "equals" };
+        assert_eq!(
+            reconcile(Some(&id), Some(&rule), &rule),
+            Reconciliation::Reuse
+        );
+    }
+
+    #[test]
+    fn reconcile_is_stale_when_node_rule_differs() {
+        let id = "scan-1".to_string();
+        let old_rule = object! { "predicate": "equals" };
+        let new_rule = object! { "predicate": "containsAsset" };
+        assert_eq!(
+            reconcile(Some(&id), Some(&old_rule), &new_rule),
+            Reconciliation::Stale
+        );
+    }
+
+    #[test]
+    fn reconcile_is_missing_when_node_has_no_such_id() {
+        let id = "scan-1".to_string();
+        let rule = object! { "predicate": "equals" };
+        assert_eq!(reconcile(Some(&id), None, &rule), Reconciliation::Missing);
+    }
+
+    #[test]
+    fn reconcile_is_missing_when_there_is_no_local_id() {
+        let rule = object! { "predicate": "equals" };
+        assert_eq!(reconcile(None, None, &rule), Reconciliation::Missing);
+    }
 }